@@ -1,39 +1,100 @@
 //! Quiet timer (Work In Progress)
-//! 
+//!
 //! Platform: STM32F411 ("black pill" board)
-//! 
+//!
 //! Constantly update a counter and display it as elapsed time.
-//! 
+//!
 //! Uses an OLED SSD1306 display with I2C interface, an LED and a button.
-//! 
+//!
 //! It counts down to zero, then blinks the LED a few times, then goes back to countdown.
-//! 
+//!
 //! Pressing the button resets the counter back to 180 seconds.
-//! 
-//! Both elapsed time and set counter time are displayed in TerminalMode.
-//! 
-//! Time update is controlled by TIM2 timer, firing every second. 
-//! Display is updated every 200 ms with less precise SysClock.
-//! 
+//!
+//! The countdown is displayed as large digits plus a horizontal progress bar
+//! in GraphicsMode, rather than the small fixed-width text TerminalMode offers.
+//!
+//! Time update is driven by the DS3231 RTC's 1 Hz square wave (see below).
+//! Display is redrawn every 200 ms with less precise SysClock.
+//!
 //! Time to count down from is set in 60-second intervals with a potentiometer/ADC
-//! 
+//!
+//! Built on RTIC (`cortex-m-rtic`): peripheral setup happens once in `#[init]`,
+//! which hands out late resources, and each interrupt becomes a `#[task]` bound
+//! to its vector with an explicit resource list. The scheduler enforces the
+//! priority ordering that used to be set by hand on the NVIC, and `set`/`elapsed`
+//! are shared resources locked for the duration of each access instead of being
+//! wrapped in ad-hoc critical sections.
+//!
+//! A DS3231 RTC sits on the same I2C bus as the display (split with `shared-bus`)
+//! and is the real time base: its 1 Hz square-wave output is wired to an EXTI
+//! line instead of using TIM2 as a software tick, so `elapsed` is recomputed
+//! from wall-clock reads rather than drifting from missed/late timer ticks.
+//! Arming the countdown (button press) stores the wall-clock target the
+//! countdown should reach; `set`/`elapsed` keep their old meaning for display
+//! purposes. `format_clock` can also render a plain HH:MM:SS reading, so the
+//! display doubles as a desk clock once a mode switch is wired up.
+//!
+//! The potentiometer/ADC path is known to fluctuate, so a quadrature rotary
+//! encoder is offered alongside it as a second, jitter-free way to set `set`:
+//! both channels are wired to EXTI lines and decoded with the standard 4-state
+//! Gray-code transition table, and each detent nudges `set` by one 60-second
+//! bucket (clamped to 0-30 minutes). The encoder's own push switch shares the
+//! board button's EXTI0 reset line.
+//!
+//! The ADC path itself is also filtered rather than trusting a single
+//! conversion: each tick's raw sample goes into an `ADC_WINDOW`-deep circular
+//! buffer, the median of the window is taken (more spike-resistant than an
+//! average), and `set` only moves to the new bucket once the median has
+//! cleared the old bucket's boundary by `ADC_HYSTERESIS`, so a reading sitting
+//! on a boundary can't flicker `set` back and forth.
+//!
+//! Expiry is now also audible: a piezo buzzer sits on a TIM4 PWM channel, and
+//! TIM2 - freed up once the DS3231 SQW line took over as the time base - is
+//! repurposed as the note-advance ticker for a small `MELODY` sequencer. Each
+//! tick either counts down the current note's remaining duration or, once it
+//! elapses, reprograms the PWM period for the next note; this keeps the
+//! melody non-blocking, and the button press (`exti0`) can silence it
+//! mid-playback the same way it resets the countdown.
+//!
+//! The LED side of expiry is an explicit `AppState` (`Idle`, `Running`,
+//! `Expired { blinks_left }`, `Holding { ticks_left }`) driven entirely from a
+//! dedicated TIM5 tick rather than the `delay_ms` loop this used to be: each
+//! tick either toggles the LED and counts down the blinks left, or counts
+//! down the hold before toggling the LED back off and returning to `Running`.
+//! Nothing blocks, so the button can cancel a blink sequence or a hold just
+//! as readily as it can reset a plain countdown.
+//!
+//! `idle` redraws with `embedded-graphics` rather than writing a padded
+//! string through `TerminalMode`: large digits (`Font12x16`) show the
+//! countdown or clock reading, and in `Countdown` mode a progress bar below
+//! them fills at `elapsed * DISPLAY_W / set`. Each region is only cleared and
+//! repainted when its own content changes, so a steady clock tick doesn't
+//! retrace the bar and a moving bar doesn't retrace the digits - this keeps
+//! I2C traffic down on the once-every-200-ms redraw.
+//!
 //! Further developments:
-//! 
+//!
 //! - use button to stop/start/reset the counter
-//! - improve the ADC reading (currently values are fluctuating a little)
-//! 
+//! - wire an input to toggle `mode` between `Countdown` and `Clock`
+//!
 //! Connections:
-//! 
-//! I2C:
+//!
+//! I2C (shared between SSD1306 and DS3231):
 //! SDA -> PB9
 //! SCL -> PB8
 //!
 //! LED: PA1
-//! 
+//!
 //! BUTTON: built-in button on PA0
-//! 
-//! ADC: PA3
-//! 
+//!
+//! ADC (potentiometer, legacy set input): PA3
+//!
+//! ROTARY ENCODER: A -> PB4 (EXTI4), B -> PB5 (EXTI9_5), switch -> shared with BUTTON
+//!
+//! DS3231 SQW (1 Hz): PB1 -> EXTI1
+//!
+//! BUZZER: PB6 (TIM4 CH1), note-advance ticker on TIM2
+//!
 //! Best results when using `--release`.
 
 #![no_std]
@@ -41,313 +102,735 @@
 
 // import all the necessary crates and components
 
-extern crate cortex_m;
-extern crate cortex_m_rt as rt;
-extern crate stm32f4xx_hal as hal;
-extern crate stm32f4;
 extern crate panic_halt;
 
-use cortex_m_rt::entry;
-use cortex_m::interrupt::{Mutex, free};
-
 use core::fmt;
 use core::fmt::Write;
 use arrayvec::ArrayString;
 
-use core::ops::DerefMut;
-use core::cell::{Cell, RefCell};
+use ssd1306::{prelude::*, Builder as SSD1306Builder};
+
+use embedded_graphics::{
+    fonts::{Font12x16, Text},
+    geometry::Point,
+    pixelcolor::BinaryColor,
+    prelude::*,
+    primitives::Rectangle,
+    style::{PrimitiveStyle, TextStyle},
+};
 
-use stm32f4::stm32f411::interrupt;
+use ds3231::DS3231;
 
-use ssd1306::{prelude::*, Builder as SSD1306Builder};
+use stm32f4xx_hal as hal;
 
 use crate::hal::{
     prelude::*,
-    gpio::{gpioa::{PA0, PA3}, Edge, ExtiPin, Input, PullUp, Analog},
+    gpio::{gpioa::{PA0, PA1, PA3}, gpiob::{PB1, PB4, PB5}, Edge, ExtiPin, Input, Floating, Output, PullUp, PushPull, Analog},
     i2c::I2c,
     stm32,
     timer::{Timer, Event},
     delay::Delay,
     time::Hertz,
-    stm32::{Interrupt,EXTI},
-    adc::{Adc, config::{AdcConfig, SampleTime, Resolution}}
+    stm32::{Interrupt, EXTI},
+    adc::{Adc, config::{AdcConfig, SampleTime, Resolution}},
+    pwm::{self, PwmChannels, C1},
 };
 
+// the I2C bus is shared between the SSD1306 display and the DS3231 RTC
+type I2cBus = I2c<stm32::I2C1, (
+    crate::hal::gpio::gpiob::PB8<crate::hal::gpio::AlternateOD<crate::hal::gpio::AF4>>,
+    crate::hal::gpio::gpiob::PB9<crate::hal::gpio::AlternateOD<crate::hal::gpio::AF4>>,
+)>;
 
-// create two globally accessible values for set and elapsed time
-static SET: Mutex<Cell<u16>> = Mutex::new(Cell::new(0u16));
-static ELAPSED: Mutex<Cell<u16>> = Mutex::new(Cell::new(0u16));
+// the DS3231 proxy is used from interrupt context (sqw/exti0) while the
+// SSD1306 proxy is used from idle, so the bus needs real mutual exclusion,
+// not just NullMutex's single-threaded RefCell borrow check
+type I2cProxy = shared_bus::I2cProxy<'static, shared_bus::CortexMMutex<I2cBus>>;
 
-// globally accessible interrupts and peripherals: timer, external interrupt and button
-static TIMER_TIM2: Mutex<RefCell<Option<Timer<stm32::TIM2>>>> = Mutex::new(RefCell::new(None));
-static EXTI: Mutex<RefCell<Option<EXTI>>> = Mutex::new(RefCell::new(None));
-static BUTTON: Mutex<RefCell<Option<PA0<Input<PullUp>>>>> = Mutex::new(RefCell::new(None));
+type Display = GraphicsMode<I2cInterface<I2cProxy>>;
 
-// interrupt and peripheral for ADC
-static TIMER_TIM3: Mutex<RefCell<Option<Timer<stm32::TIM3>>>> = Mutex::new(RefCell::new(None));
+// the piezo buzzer, driven by TIM4 channel 1 on PB6
+type Buzzer = PwmChannels<stm32::TIM4, C1>;
 
-static GADC: Mutex<RefCell<Option<Adc<stm32::ADC1>>>> = Mutex::new(RefCell::new(None));
-static ANALOG: Mutex<RefCell<Option<PA3<Analog>>>> = Mutex::new(RefCell::new(None));
+// which reading `idle` renders: the countdown (with its progress bar) or a
+// plain clock
+#[derive(Clone, Copy, PartialEq)]
+enum DisplayMode {
+    Countdown,
+    Clock,
+}
+
+// 128x32 display geometry, and the rows the large digits and the countdown
+// progress bar each occupy; kept apart so redrawing one never disturbs the other
+const DISPLAY_W: i32 = 128;
+const DIGITS_Y: i32 = 4;
+const DIGITS_H: i32 = 16;
+const BAR_Y: i32 = 26;
+const BAR_H: i32 = 4;
+
+// drives the yellow LED through expiry without ever blocking `idle` or the
+// button: counting blinks and the hold afterwards both happen one TIM5 tick
+// at a time
+#[derive(Clone, Copy)]
+enum AppState {
+    Idle,
+    Running,
+    Expired { blinks_left: u8 },
+    Holding { ticks_left: u16 },
+}
+
+// LED blink tick rate and how many toggles make up the expiry blink sequence
+// (odd, so the LED ends up lit after the last toggle)
+const BLINK_TICK_MS: u32 = 100;
+const BLINK_COUNT: u8 = 11;
+
+// how long the LED is held lit after blinking, in BLINK_TICK_MS ticks
+const HOLD_TICKS: u16 = (3000 / BLINK_TICK_MS) as u16;
+
+// standard quadrature Gray-code transition table: index with
+// `(prev_state << 2) | curr_state`, where each state is `(a << 1) | b`,
+// to get the -1/0/+1 step for that edge
+const QUADRATURE_STEPS: [i8; 16] = [
+    0, -1, 1, 0,
+    1, 0, 0, -1,
+    -1, 0, 0, 1,
+    0, 1, -1, 0,
+];
+
+// number of valid quadrature steps per mechanical detent
+const STEPS_PER_DETENT: i8 = 4;
+
+// maximum `set` value: 30 minutes in 60-second buckets; the ADC path's
+// `(sample >> 1) * 60` mapping is clamped to the same limit
+const SET_MAX_SECONDS: u16 = 30 * 60;
+
+// seconds-of-day wraps at midnight; `target`/`now` need to be compared modulo
+// this so arming the countdown near end of day doesn't pin `elapsed` until
+// the clock catches back up to the pre-rollover target
+const SECONDS_PER_DAY: u32 = 24 * 3600;
+
+// decode one encoder edge against the transition table, accumulating steps
+// in `accum` until a full mechanical detent (`STEPS_PER_DETENT` valid steps)
+// has been seen; returns the +1/-1 bucket change for that detent, if any
+fn quadrature_step(state: &mut u8, accum: &mut i8, a: bool, b: bool) -> Option<i16> {
+    let curr_state = ((a as u8) << 1) | (b as u8);
+    let index = ((*state << 2) | curr_state) as usize;
+    *state = curr_state;
+
+    *accum += QUADRATURE_STEPS[index];
+
+    if *accum >= STEPS_PER_DETENT {
+        *accum = 0;
+        Some(1)
+    } else if *accum <= -STEPS_PER_DETENT {
+        *accum = 0;
+        Some(-1)
+    } else {
+        None
+    }
+}
+
+// apply a +1/-1 bucket step to `set`, clamped to the 0-30 minute range
+fn apply_bucket_step(set: u16, bucket_step: i16) -> u16 {
+    let buckets = (set / 60) as i16 + bucket_step;
+    let clamped = buckets.max(0).min((SET_MAX_SECONDS / 60) as i16);
+    clamped as u16 * 60
+}
+
+// number of raw ADC conversions kept in the median filter window
+const ADC_WINDOW: usize = 8;
+
+// hysteresis margin, in raw 6-bit ADC counts (roughly half an LSB of the
+// 2-count-wide bucket), that a reading must clear before `set` moves buckets
+const ADC_HYSTERESIS: i16 = 1;
+
+// median of the first `len` entries of `samples`; `len` is always
+// `<= ADC_WINDOW`, so the small fixed-size insertion sort below is cheap
+// enough to run on every tick
+fn median_of(samples: &[u8]) -> u8 {
+    let len = samples.len();
+    let mut sorted = [0u8; ADC_WINDOW];
+    sorted[..len].copy_from_slice(samples);
+
+    for i in 1..len {
+        let key = sorted[i];
+        let mut j = i;
+        while j > 0 && sorted[j - 1] > key {
+            sorted[j] = sorted[j - 1];
+            j -= 1;
+        }
+        sorted[j] = key;
+    }
+
+    if len % 2 == 0 {
+        ((sorted[len / 2 - 1] as u16 + sorted[len / 2] as u16) / 2) as u8
+    } else {
+        sorted[len / 2]
+    }
+}
+
+// debounce the raw bucket reading against the currently committed bucket:
+// a new bucket is only accepted once the median has cleared the old bucket's
+// boundary by `ADC_HYSTERESIS`, so noise sitting on the boundary can't flip
+// `set` back and forth every tick
+fn debounce_bucket(committed_bucket: u16, median: u8) -> u16 {
+    let candidate_bucket = (median >> 1) as u16;
+    if candidate_bucket == committed_bucket {
+        return committed_bucket;
+    }
+
+    let boundary = committed_bucket as i16 * 2;
+    let median = median as i16;
+
+    if candidate_bucket > committed_bucket {
+        if median - boundary >= 2 + ADC_HYSTERESIS { candidate_bucket } else { committed_bucket }
+    } else {
+        if boundary - median >= 1 + ADC_HYSTERESIS { candidate_bucket } else { committed_bucket }
+    }
+}
+
+// how often the note-advance timer ticks; also the smallest unit a note's
+// remaining duration is tracked in
+const NOTE_TICK_MS: u16 = 10;
+
+// short rising alarm melody: (frequency_hz, duration_ms) pairs, played once
+// per expiry and cancellable mid-playback by the button
+const MELODY: &[(u16, u16)] = &[
+    (880, 150),
+    (988, 150),
+    (1047, 150),
+    (1175, 300),
+];
+
+// start the alarm: rewind the sequencer to the first note and drive the PWM
+// channel at its pitch; called from the `sqw` task the instant `elapsed`
+// reaches zero
+fn start_alarm(buzzer: &mut Buzzer, melody_idx: &mut usize, note_remaining_ms: &mut u16, playing: &mut bool) {
+    *melody_idx = 0;
+    *note_remaining_ms = MELODY[0].1;
+    *playing = true;
+
+    buzzer.set_period(Hertz(MELODY[0].0 as u32));
+    buzzer.set_duty(buzzer.get_max_duty() / 2);
+    buzzer.enable();
+}
+
+// stop the alarm immediately, silencing the PWM channel; called both when
+// the melody runs out and when the button cancels it mid-playback
+fn stop_alarm(buzzer: &mut Buzzer, playing: &mut bool) {
+    *playing = false;
+    buzzer.disable();
+}
+
+#[rtic::app(device = crate::hal::stm32, peripherals = true)]
+const APP: () = {
+
+    struct Resources {
+
+        // set and elapsed time, shared between the ISRs and `idle`
+        set: u16,
+        elapsed: u16,
+
+        // whether `idle` renders the countdown (with its progress bar) or the clock
+        mode: DisplayMode,
+
+        // expiry LED sequencing, advanced one tick at a time by the blink task
+        state: AppState,
+        timer_tim5: Timer<stm32::TIM5>,
+
+        // the DS3231 RTC, shared between the SQW tick task, the button task and `idle`
+        rtc: DS3231<I2cProxy>,
+
+        // wall-clock second-of-day the countdown should reach; armed at boot and
+        // re-armed by every button press
+        target: Option<u32>,
+
+        // timer and external interrupts, owned by their respective tasks
+        timer_tim3: Timer<stm32::TIM3>,
+        exti: EXTI,
+        button: PA0<Input<PullUp>>,
+        sqw: PB1<Input<Floating>>,
+
+        // ADC and its analog input pin, owned by the TIM3 task
+        gadc: Adc<stm32::ADC1>,
+        analog: PA3<Analog>,
+
+        // ADC median filter window and the bucket it last committed to `set`
+        adc_window: [u8; ADC_WINDOW],
+        adc_window_idx: usize,
+        adc_window_len: usize,
+        adc_bucket: u16,
+
+        // rotary encoder channels and decoder state, owned by the encoder tasks
+        enc_a: PB4<Input<PullUp>>,
+        enc_b: PB5<Input<PullUp>>,
+        enc_state: u8,
+        enc_accum: i8,
+
+        // piezo buzzer and its note-advance timer/sequencer state
+        buzzer: Buzzer,
+        timer_tim2: Timer<stm32::TIM2>,
+        melody_idx: usize,
+        note_remaining_ms: u16,
+        buzzer_playing: bool,
+
+        // display, only ever touched from `idle`
+        disp: Display,
+        // the LED itself, only ever touched from the blink task
+        yellow: PA1<Output<PushPull>>,
+        delay: Delay,
+    }
+
+    #[init]
+    fn init(cx: init::Context) -> init::LateResources {
+
+        let mut dp = cx.device;
 
-#[entry]
-fn main() -> ! {
-    if let (Some(mut dp), Some(cp)) = (
-        stm32::Peripherals::take(),
-        cortex_m::peripheral::Peripherals::take(),
-    ) {
-        
         // necessary to enable this for the external interrupt to work
-        dp.RCC.apb2enr.write(|w| w.syscfgen().enabled()); 
+        dp.RCC.apb2enr.write(|w| w.syscfgen().enabled());
 
         // set up clocks
         let rcc = dp.RCC.constrain();
         let clocks = rcc.cfgr.sysclk(48.mhz()).freeze();
 
-      // Set up I2C - SCL is PB8 and SDA is PB9; they are set to Alternate Function 4, open drain
+        // Set up I2C - SCL is PB8 and SDA is PB9; they are set to Alternate Function 4, open drain
+        // the bus is shared: the SSD1306 and the DS3231 each get their own proxy handle
         let gpiob = dp.GPIOB.split();
         let scl = gpiob.pb8.into_alternate_af4().set_open_drain();
         let sda = gpiob.pb9.into_alternate_af4().set_open_drain();
-        let i2c = I2c::i2c1(dp.I2C1, (scl, sda), 400.khz(), clocks);
+        let i2c: I2cBus = I2c::i2c1(dp.I2C1, (scl, sda), 400.khz(), clocks);
+        let bus = shared_bus::new_cortexm!(I2cBus = i2c).unwrap();
 
-        //set up LED on pin PA1
+        // set up LED on pin PA1
         let gpioa = dp.GPIOA.split();
-        let mut yellow = gpioa.pa1.into_push_pull_output();
-        
-        //set up the on-board button on PA0
+        let yellow = gpioa.pa1.into_push_pull_output();
+
+        // set up the on-board button on PA0
         let mut board_btn = gpioa.pa0.into_pull_up_input();
         board_btn.make_interrupt_source(&mut dp.SYSCFG);
         board_btn.enable_interrupt(&mut dp.EXTI);
         board_btn.trigger_on_edge(&mut dp.EXTI, Edge::FALLING);
 
-        // Set up ADC
+        // DS3231 SQW output on PB1, configured for a 1 Hz square wave and
+        // wired to EXTI1 as the new, more accurate time base
+        let mut sqw = gpiob.pb1.into_floating_input();
+        sqw.make_interrupt_source(&mut dp.SYSCFG);
+        sqw.enable_interrupt(&mut dp.EXTI);
+        sqw.trigger_on_edge(&mut dp.EXTI, Edge::RISING);
+
+        let mut rtc = DS3231::new(bus.acquire_i2c(), 0x68);
+        rtc.enable_1hz_square_wave().unwrap();
+
+        // arm the initial 180-second countdown against the RTC, the same way
+        // a button press does
+        let boot_secs = seconds_of_day(rtc.get_time().unwrap());
+
+        // rotary encoder channels A and B, one falling/rising edge per detent
+        // quadrant; the encoder's push switch is wired to the same net as the
+        // board button so it shares the EXTI0 reset handler as-is
+        let mut enc_a = gpiob.pb4.into_pull_up_input();
+        enc_a.make_interrupt_source(&mut dp.SYSCFG);
+        enc_a.enable_interrupt(&mut dp.EXTI);
+        enc_a.trigger_on_edge(&mut dp.EXTI, Edge::RISING_FALLING);
+
+        let mut enc_b = gpiob.pb5.into_pull_up_input();
+        enc_b.make_interrupt_source(&mut dp.SYSCFG);
+        enc_b.enable_interrupt(&mut dp.EXTI);
+        enc_b.trigger_on_edge(&mut dp.EXTI, Edge::RISING_FALLING);
 
+        // Set up ADC
         let adcconfig = AdcConfig::default().resolution(Resolution::Six);
         let adc = Adc::adc1(dp.ADC1, true, adcconfig);
-        
-        let pa3 = gpioa.pa3.into_analog();
 
+        let pa3 = gpioa.pa3.into_analog();
 
-        // move the PA3 pin and the ADC into the 'global storage'
-
-        free(|cs| {
-            *GADC.borrow(cs).borrow_mut() = Some(adc);
-            *ANALOG.borrow(cs).borrow_mut() = Some(pa3);
-        });
+        // Set up the display: graphics mode with 128x32 display, for the
+        // large-digit countdown and progress bar
+        let mut disp: Display = SSD1306Builder::new().size(DisplaySize::Display128x32).connect_i2c(bus.acquire_i2c()).into();
 
-        // Set up the display: using terminal mode with 128x32 display
-        let mut disp: TerminalMode<_> = SSD1306Builder::new().size(DisplaySize::Display128x32).connect_i2c(i2c).into();
-        
         disp.init().unwrap();
         disp.clear().unwrap();
 
         // set up delay provider
-        let mut delay = Delay::new(cp.SYST, clocks);
-
+        let delay = Delay::new(cx.core.SYST, clocks);
 
-        // set up timers and external interrupt
+        // set up the ADC sampling timer and the external interrupt
 
-        let mut timer = Timer::tim2(dp.TIM2, Hertz(1), clocks);
-        timer.listen(Event::TimeOut);
+        let mut timer_tim3 = Timer::tim3(dp.TIM3, Hertz(10), clocks); // adc update every 100ms
+        timer_tim3.listen(Event::TimeOut);
 
-        let mut adctimer = Timer::tim3(dp.TIM3, Hertz(10), clocks); //adc update every 100ms
-        adctimer.listen(Event::TimeOut);
-        
-        let exti = dp.EXTI;
-
-        free(|cs| {
-            TIMER_TIM2.borrow(cs).replace(Some(timer));
-            TIMER_TIM3.borrow(cs).replace(Some(adctimer));
-            EXTI.borrow(cs).replace(Some(exti));
-            BUTTON.borrow(cs).replace(Some(board_btn));
-        });
+        // TIM2 is free now that the DS3231 SQW line is the time base, so it
+        // is repurposed as the buzzer's note-advance ticker
+        let mut timer_tim2 = Timer::tim2(dp.TIM2, Hertz(1000 / NOTE_TICK_MS as u32), clocks);
+        timer_tim2.listen(Event::TimeOut);
 
+        // piezo buzzer on PB6 (TIM4 channel 1), silent until an alarm starts
+        let buzzer_pin = gpiob.pb6.into_alternate_af2();
+        let mut buzzer = pwm::tim4(dp.TIM4, buzzer_pin, clocks, Hertz(MELODY[0].0 as u32));
+        buzzer.disable();
 
-        let mut nvic = cp.NVIC;
-            unsafe {
-                nvic.set_priority(Interrupt::TIM2, 1);
-                cortex_m::peripheral::NVIC::unmask(Interrupt::TIM2);
+        // dedicated blink-sequence ticker, replacing the old `delay_ms` loop
+        let mut timer_tim5 = Timer::tim5(dp.TIM5, Hertz(1000 / BLINK_TICK_MS), clocks);
+        timer_tim5.listen(Event::TimeOut);
 
-                nvic.set_priority(Interrupt::EXTI0, 3);
-                cortex_m::peripheral::NVIC::unmask(Interrupt::EXTI0);
+        let exti = dp.EXTI;
 
-                nvic.set_priority(Interrupt::TIM3, 2);
-                cortex_m::peripheral::NVIC::unmask(Interrupt::TIM3);
+        // priorities are declared per task below and enforced by RTIC;
+        // no manual NVIC::set_priority/unmask calls are needed any more
 
-            }
-            
-            cortex_m::peripheral::NVIC::unpend(Interrupt::TIM2);
-            cortex_m::peripheral::NVIC::unpend(Interrupt::TIM3);
-            cortex_m::peripheral::NVIC::unpend(Interrupt::EXTI0);
-                    
         // set the counter to some value, in this case 3 minutes
         // count down as long as the value > 0
-        
 
-        free(|cs| SET.borrow(cs).set(180));
+        init::LateResources {
+            set: 180,
+            elapsed: 180,
+            mode: DisplayMode::Countdown,
+            state: AppState::Idle,
+            timer_tim5,
+            rtc,
+            target: Some((boot_secs + 180) % SECONDS_PER_DAY),
+            timer_tim3,
+            exti,
+            button: board_btn,
+            sqw,
+            gadc: adc,
+            analog: pa3,
+            adc_window: [0u8; ADC_WINDOW],
+            adc_window_idx: 0,
+            adc_window_len: 0,
+            adc_bucket: 180 / 60,
+            enc_a,
+            enc_b,
+            enc_state: 0,
+            enc_accum: 0,
+            buzzer,
+            timer_tim2,
+            melody_idx: 0,
+            note_remaining_ms: 0,
+            buzzer_playing: false,
+            disp,
+            yellow,
+            delay,
+        }
+    }
 
-        loop {
-            
-            free(|cs| ELAPSED.borrow(cs).set(SET.borrow(cs).get()));
+    // the idle task drives the display for as long as there is nothing else to do;
+    // it replaces the old `main()` super-loop. Redraws every 200 ms, and only
+    // the digits region or the bar region - whichever actually changed.
+    #[idle(resources = [set, elapsed, mode, rtc, disp, delay])]
+    fn idle(mut cx: idle::Context) -> ! {
 
-            while free(|cs| ELAPSED.borrow(cs).get()) > 0 {
+        let mut last_digits: Option<(u8, u8, u8)> = None;
+        let mut last_bar: Option<(u16, u16)> = None;
 
-                // create an empty buffer for the display
-                let mut buffer = ArrayString::<[u8; 64]>::new();
+        loop {
 
-                // get the values from the global variables
-                let elapsed = free(|cs| ELAPSED.borrow(cs).get()); 
-                let set = free(|cs| SET.borrow(cs).get()); 
+            // get the values from the shared resources
+            let elapsed = cx.resources.elapsed.lock(|elapsed| *elapsed);
+            let set = cx.resources.set.lock(|set| *set);
+            let mode = *cx.resources.mode;
+
+            let digits = match mode {
+                DisplayMode::Countdown => time_digits(elapsed),
+                DisplayMode::Clock => {
+                    let now = cx.resources.rtc.lock(|rtc| rtc.get_time().unwrap());
+                    (now.hours, now.minutes, now.seconds)
+                }
+            };
+
+            if last_digits != Some(digits) {
+                let mut buffer = ArrayString::<[u8; 8]>::new();
+                format_clock(&mut buffer, digits.0, digits.1, digits.2);
+                redraw_digits(cx.resources.disp, buffer.as_str());
+                last_digits = Some(digits);
+            }
 
-                // convert the seconds to hh:mm:ss format
+            let bar = match mode {
+                DisplayMode::Countdown => Some((elapsed, set)),
+                DisplayMode::Clock => None,
+            };
+
+            if last_bar != bar {
+                match bar {
+                    Some((elapsed, set)) => redraw_bar(cx.resources.disp, elapsed, set),
+                    None => clear_bar(cx.resources.disp),
+                }
+                last_bar = bar;
+            }
 
-                let (e_hrs, e_mins, e_secs) = time_digits(elapsed);
-                let (s_hrs, s_mins, s_secs) = time_digits(set);
-                
-                // convert the seconds to hh:mm:ss format
+            cx.resources.delay.delay_ms(200_u16);
+        }
+    }
+
+    // the DS3231's 1 Hz square wave is the new time base: elapsed is recomputed
+    // from the wall-clock target rather than decremented tick by tick, so a
+    // missed or late edge can't make the countdown drift. Reaching zero kicks
+    // off the alarm melody.
+    #[task(binds = EXTI1, priority = 1, resources = [sqw, exti, rtc, target, elapsed, buzzer, melody_idx, note_remaining_ms, buzzer_playing, state])]
+    fn sqw(mut cx: sqw::Context) {
+
+        let sqw_pin = cx.resources.sqw;
+        cx.resources.exti.lock(|exti| sqw_pin.clear_interrupt_pending_bit(exti));
+
+        let now = seconds_of_day(cx.resources.rtc.get_time().unwrap());
+
+        let target = *cx.resources.target;
+        let new_elapsed = match target {
+            // target may be on the other side of a midnight rollover from
+            // `now`, so compare the two modulo a full day rather than
+            // assuming `target_secs > now`
+            Some(target_secs) => {
+                let remaining = (target_secs + SECONDS_PER_DAY - now) % SECONDS_PER_DAY;
+                if remaining > 0 && remaining <= SET_MAX_SECONDS as u32 { remaining as u16 } else { 0 }
+            }
+            None => 0,
+        };
+        let elapsed = cx.resources.elapsed;
+        let was_running = *elapsed > 0;
+        *elapsed = new_elapsed;
+
+        if was_running && *elapsed == 0 {
+            let melody_idx = cx.resources.melody_idx;
+            let note_remaining_ms = cx.resources.note_remaining_ms;
+            let buzzer_playing = cx.resources.buzzer_playing;
+            cx.resources.buzzer.lock(|buzzer| {
+                melody_idx.lock(|melody_idx| {
+                    note_remaining_ms.lock(|note_remaining_ms| {
+                        buzzer_playing.lock(|playing| start_alarm(buzzer, melody_idx, note_remaining_ms, playing));
+                    });
+                });
+            });
+
+            *cx.resources.state = AppState::Expired { blinks_left: BLINK_COUNT };
+        }
+    }
 
-                format_time(&mut buffer, elapsed, set);
+    #[task(binds = EXTI0, priority = 1, resources = [button, exti, rtc, set, elapsed, target, buzzer, buzzer_playing, state])]
+    fn exti0(mut cx: exti0::Context) {
 
-                
-                disp.write_str(buffer.as_str()).unwrap();
-                
-                //delay.delay_ms(200_u16);
+        let button = cx.resources.button;
+        cx.resources.exti.lock(|exti| button.clear_interrupt_pending_bit(exti));
 
-            }
+        // silence the alarm if it's playing, the same press that resets the countdown
+        let buzzer_playing = cx.resources.buzzer_playing;
+        let was_playing = buzzer_playing.lock(|playing| *playing);
+        if was_playing {
+            let buzzer = cx.resources.buzzer;
+            buzzer.lock(|buzzer| {
+                buzzer_playing.lock(|playing| stop_alarm(buzzer, playing));
+            });
+        }
 
-            // display zeros when the time is up
-            
-            let mut buffer = ArrayString::<[u8; 64]>::new();
+        // cancel any in-progress blink/hold sequence; the LED state machine
+        // is driven purely by ticks, so this takes effect on the next one
+        *cx.resources.state = AppState::Running;
 
-            let zero: u16 = 0;
+        // arm the countdown: store the wall-clock second-of-day it should
+        // reach, and reset the displayed elapsed value back to the set value
+        let timeset = cx.resources.set.lock(|set| *set);
+        let now = seconds_of_day(cx.resources.rtc.get_time().unwrap());
 
-            let set = free(|cs| SET.borrow(cs).get()); 
+        *cx.resources.target = Some((now + timeset as u32) % SECONDS_PER_DAY);
+        *cx.resources.elapsed = timeset;
+    }
 
-            let (s_hrs, s_mins, s_secs) = time_digits(set);
+    // drives the expiry LED sequence off its own `BLINK_TICK_MS` ticker:
+    // `Expired` blinks the LED `BLINK_COUNT` times, then `Holding` leaves it
+    // on for `HOLD_TICKS` before returning to `Running`. A no-op in any
+    // other state, so this never fights with a countdown that's still ticking.
+    #[task(binds = TIM5, priority = 1, resources = [timer_tim5, state, yellow])]
+    fn blink(mut cx: blink::Context) {
 
-            format_time(&mut buffer, zero, set);
-                
-            disp.write_str(buffer.as_str()).unwrap();
-                
-            // blink LED a few times, then leave it on
+        cx.resources.timer_tim5.clear_interrupt(Event::TimeOut);
 
-            for _ in 0..11 { //odd number to keep the LED on after it's done blinking
+        let yellow = cx.resources.yellow;
+        cx.resources.state.lock(|state| match *state {
+            AppState::Expired { blinks_left } => {
                 yellow.toggle().unwrap();
-                delay.delay_ms(100_u16);
+                *state = if blinks_left > 1 {
+                    AppState::Expired { blinks_left: blinks_left - 1 }
+                } else {
+                    AppState::Holding { ticks_left: HOLD_TICKS }
+                };
             }
+            AppState::Holding { ticks_left } => {
+                *state = if ticks_left > 1 {
+                    AppState::Holding { ticks_left: ticks_left - 1 }
+                } else {
+                    yellow.toggle().unwrap();
+                    AppState::Running
+                };
+            }
+            AppState::Idle | AppState::Running => {}
+        });
+    }
+
+    // advances the alarm melody by one `NOTE_TICK_MS` slice; reprograms the
+    // PWM period when a note's duration runs out, and silences the buzzer
+    // once the melody is done. A no-op while the alarm isn't playing.
+    #[task(binds = TIM2, priority = 3, resources = [timer_tim2, buzzer, melody_idx, note_remaining_ms, buzzer_playing])]
+    fn tim2(cx: tim2::Context) {
+
+        cx.resources.timer_tim2.clear_interrupt(Event::TimeOut);
+
+        if !*cx.resources.buzzer_playing {
+            return;
+        }
 
-            delay.delay_ms(3000_u16);
+        let note_remaining_ms = cx.resources.note_remaining_ms;
+        if *note_remaining_ms > NOTE_TICK_MS {
+            *note_remaining_ms -= NOTE_TICK_MS;
+            return;
+        }
+
+        let melody_idx = cx.resources.melody_idx;
+        *melody_idx += 1;
 
-            yellow.toggle().unwrap();
-        
+        if *melody_idx >= MELODY.len() {
+            stop_alarm(cx.resources.buzzer, cx.resources.buzzer_playing);
+            return;
         }
 
+        let (frequency_hz, duration_ms) = MELODY[*melody_idx];
+        *note_remaining_ms = duration_ms;
+        let buzzer = cx.resources.buzzer;
+        buzzer.set_period(Hertz(frequency_hz as u32));
+        buzzer.set_duty(buzzer.get_max_duty() / 2);
     }
-    
-    loop {}
-}
 
-#[interrupt]
+    // the set value gets updated every time the interrupt fires
+    // it is read from ADC on pin PA3, oversampled and median-filtered to
+    // stop `set` flickering between adjacent 60-second buckets
+    #[task(binds = TIM3, priority = 2, resources = [timer_tim3, gadc, analog, set, adc_window, adc_window_idx, adc_window_len, adc_bucket])]
+    fn tim3(cx: tim3::Context) {
+
+        cx.resources.timer_tim3.clear_interrupt(Event::TimeOut);
 
-// the ELAPSED value gets updated every second when the interrupt fires
+        let sample = cx.resources.gadc.convert(cx.resources.analog, SampleTime::Cycles_480);
 
-fn TIM2() {
+        // push the new conversion into the circular window
+        let idx = *cx.resources.adc_window_idx;
+        cx.resources.adc_window[idx] = sample;
+        *cx.resources.adc_window_idx = (idx + 1) % ADC_WINDOW;
+        if *cx.resources.adc_window_len < ADC_WINDOW {
+            *cx.resources.adc_window_len += 1;
+        }
+
+        let median = median_of(&cx.resources.adc_window[..*cx.resources.adc_window_len]);
 
-    // enter critical section
+        // bitshift to the right by 1 bit, converting the result to 0-31 values
+        // so the timer can be set in 60-second intervals up to 30 minutes
 
-    free(|cs| {
-        stm32::NVIC::unpend(Interrupt::TIM2);
-        if let Some(ref mut tim2) = TIMER_TIM2.borrow(cs).borrow_mut().deref_mut() {
-            tim2.clear_interrupt(Event::TimeOut);
+        let new_bucket = debounce_bucket(*cx.resources.adc_bucket, median);
+        if new_bucket != *cx.resources.adc_bucket {
+            *cx.resources.adc_bucket = new_bucket;
+            *cx.resources.set = new_bucket.min(SET_MAX_SECONDS / 60) * 60;
         }
+    }
 
-        // decrease the ELAPSED value by 1 second
+    // decode one A-channel edge and apply a bucket change once enough valid
+    // quadrature steps have accumulated for a full detent
+    #[task(binds = EXTI4, priority = 2, resources = [enc_a, enc_b, exti, enc_state, enc_accum, set])]
+    fn encoder_a(cx: encoder_a::Context) {
 
-        ELAPSED.borrow(cs).set(ELAPSED.borrow(cs).get() - 1);
-        
-    });
-    
-}
+        let (a, b) = (cx.resources.enc_a.is_high().unwrap(), cx.resources.enc_b.is_high().unwrap());
+        let enc_a = cx.resources.enc_a;
+        enc_a.clear_interrupt_pending_bit(cx.resources.exti);
 
+        if let Some(step) = quadrature_step(cx.resources.enc_state, cx.resources.enc_accum, a, b) {
+            *cx.resources.set = apply_bucket_step(*cx.resources.set, step);
+        }
+    }
 
-#[interrupt]
+    // decode one B-channel edge the same way; EXTI5 shares the EXTI9_5 vector
+    #[task(binds = EXTI9_5, priority = 2, resources = [enc_a, enc_b, exti, enc_state, enc_accum, set])]
+    fn encoder_b(cx: encoder_b::Context) {
 
-fn EXTI0() {
+        let (a, b) = (cx.resources.enc_a.is_high().unwrap(), cx.resources.enc_b.is_high().unwrap());
+        let enc_b = cx.resources.enc_b;
+        enc_b.clear_interrupt_pending_bit(cx.resources.exti);
 
-    // Enter critical section
+        if let Some(step) = quadrature_step(cx.resources.enc_state, cx.resources.enc_accum, a, b) {
+            *cx.resources.set = apply_bucket_step(*cx.resources.set, step);
+        }
+    }
+};
 
-    free(|cs| {
-        // Obtain all Mutex protected resources
+// helper function to convert seconds to hours, minutes and seconds
 
-        if let (&mut Some(ref mut btn), &mut Some(ref mut exti)) = (
-            BUTTON.borrow(cs).borrow_mut().deref_mut(),            
-            EXTI.borrow(cs).borrow_mut().deref_mut()) {
-         
-            btn.clear_interrupt_pending_bit(exti);
+fn time_digits(time: u16) -> (u8, u8, u8) {
 
-            // set the ELAPSED value back to the SET value
+    let hours = time / 3600;
+    let minutes = time / 60;
+    let seconds = time % 60;
 
-            let timeset = SET.borrow(cs).get();
+    (hours as u8, minutes as u8, seconds as u8)
+}
 
-            ELAPSED.borrow(cs).replace(timeset);
+// plain "HH:MM:SS" reading, used for both the countdown and the clock - in
+// GraphicsMode there's no fixed-width grid to pad out, `redraw_digits` clears
+// the whole digits region itself before repainting
 
-        }
-        
-    });
+fn format_clock(buf: &mut ArrayString<[u8; 8]>, hours: u8, minutes: u8, seconds: u8) {
 
+    fmt::write(buf, format_args!("{:02}:{:02}:{:02}", hours, minutes, seconds)).unwrap();
 }
 
+// clears the digits region and repaints it with `text` in `Font12x16`; only
+// called when the digits actually changed, so a steady clock reading doesn't
+// repaint every 200 ms tick
 
-#[interrupt]
+fn redraw_digits(disp: &mut Display, text: &str) {
 
-// the SET value gets updated every time the interrupt fires 
-// it is read from ADC on pin PA3
+    Rectangle::new(Point::new(0, DIGITS_Y), Point::new(DISPLAY_W - 1, DIGITS_Y + DIGITS_H - 1))
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+        .draw(disp).unwrap();
 
-fn TIM3() {
-        
-    free(|cs| {
-        stm32::NVIC::unpend(Interrupt::TIM3);
-        if let (Some(ref mut tim3), Some(ref mut adc), Some(ref mut analog)) = (
-        TIMER_TIM3.borrow(cs).borrow_mut().deref_mut(),
-        GADC.borrow(cs).borrow_mut().deref_mut(),
-        ANALOG.borrow(cs).borrow_mut().deref_mut())
-        {
-            tim3.clear_interrupt(Event::TimeOut);
+    Text::new(text, Point::new(0, DIGITS_Y))
+        .into_styled(TextStyle::new(Font12x16, BinaryColor::On))
+        .draw(disp).unwrap();
 
-            let sample = adc.convert(analog, SampleTime::Cycles_480);
+    disp.flush().unwrap();
+}
+
+// clears the bar region and repaints the filled portion, `elapsed * DISPLAY_W
+// / set` wide, so the bar shrinks in step with the countdown and reaches zero
+// exactly when the alarm does
 
-            // bitshift to the right by 1 bit, converting the result to 0-31 values
-            // so the timer can be set in 60-second intervals up to 30 minutes
+fn redraw_bar(disp: &mut Display, elapsed: u16, set: u16) {
 
-            SET.borrow(cs).replace((sample>>1)*60);
-        
+    Rectangle::new(Point::new(0, BAR_Y), Point::new(DISPLAY_W - 1, BAR_Y + BAR_H - 1))
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+        .draw(disp).unwrap();
+
+    if set > 0 {
+        let fill_w = (elapsed as u32 * DISPLAY_W as u32 / set as u32).min(DISPLAY_W as u32);
+        if fill_w > 0 {
+            Rectangle::new(Point::new(0, BAR_Y), Point::new(fill_w as i32 - 1, BAR_Y + BAR_H - 1))
+                .into_styled(PrimitiveStyle::with_fill(BinaryColor::On))
+                .draw(disp).unwrap();
         }
-        
-    });
-    
+    }
+
+    disp.flush().unwrap();
 }
 
+// blanks the bar region entirely; used in `Clock` mode, where there is no
+// countdown to show progress for
 
-// helper function for the display
-// in TerminalMode there are 64 characters in 4 lines (128x32 display, 8x8 characters)
-// to avoid the content being moved accross the display with every update
-// the buffer content must always be 64 characters long
+fn clear_bar(disp: &mut Display) {
 
-fn format_time(buf: &mut ArrayString<[u8; 64]>, elapsed: u16, set: u16) {
-    
-    let (e_hrs, e_mins, e_secs) = time_digits(elapsed);
-    let (s_hrs, s_mins, s_secs) = time_digits(set);
+    Rectangle::new(Point::new(0, BAR_Y), Point::new(DISPLAY_W - 1, BAR_Y + BAR_H - 1))
+        .into_styled(PrimitiveStyle::with_fill(BinaryColor::Off))
+        .draw(disp).unwrap();
 
-    fmt::write(buf, format_args!("    {:02}:{:02}:{:02}                                        {:02}:{:02}:{:02}    ",
-    e_hrs, e_mins, e_secs, s_hrs, s_mins, s_secs)).unwrap();
+    disp.flush().unwrap();
 }
 
-// helper function to convert seconds to hours, minutes and seconds    
+// the DS3231 driver reports plain 24h hours/minutes/seconds; fold that into
+// a single second-of-day count so the countdown target can be compared with
+// a subtraction modulo `SECONDS_PER_DAY`
 
-fn time_digits(time: u16) -> (u8, u8, u8) {
-    
-    let hours = time / 3600;
-    let minutes = time / 60;
-    let seconds = time % 60;
-
-    (hours as u8, minutes as u8, seconds as u8)
+fn seconds_of_day(time: ds3231::Time) -> u32 {
+    time.hours as u32 * 3600 + time.minutes as u32 * 60 + time.seconds as u32
 }